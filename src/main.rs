@@ -1,7 +1,20 @@
+use std::collections::HashSet;
 use std::error::Error;
 use std::io;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::{TcpListener, TcpStream};
+use std::io::Write as _;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Instant;
+
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use log::{info, warn};
+use rustls_pemfile::{certs, private_key};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use tokio_rustls::rustls::ServerConfig;
+use tokio_rustls::TlsAcceptor;
 
 use nom::{branch::alt, bytes::complete::*, multi::*, IResult};
 
@@ -20,191 +33,759 @@ struct Header {
     value: String,
 }
 
+/// Caps on how many bytes a connection is allowed to make us buffer for a
+/// single request, so that a client can't force unbounded memory growth.
+#[derive(Clone, Copy)]
+struct Limits {
+    max_header_bytes: usize,
+    max_body_bytes: usize,
+}
+
+const DEFAULT_MAX_HEADER_BYTES: usize = 8 * 1024;
+const DEFAULT_MAX_BODY_BYTES: usize = 10 * 1024 * 1024;
+
 #[tokio::main]
 async fn main() -> io::Result<()> {
+    env_logger::init();
+
     let listener = TcpListener::bind("127.0.0.1:4221").await?;
 
-    let mut args = std::env::args();
     let mut directory = None;
+    let mut tls_cert = None;
+    let mut tls_key = None;
+    let mut log_ips = false;
+    let mut limits = Limits {
+        max_header_bytes: DEFAULT_MAX_HEADER_BYTES,
+        max_body_bytes: DEFAULT_MAX_BODY_BYTES,
+    };
 
-    if let Some(arg) = args.nth(1) {
-        if arg == "--directory" {
-            directory = Some(args.nth(0).unwrap());
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--directory" => directory = args.next(),
+            "--tls-cert" => tls_cert = args.next(),
+            "--tls-key" => tls_key = args.next(),
+            "--log-ips" => log_ips = true,
+            "--max-header-bytes" => {
+                if let Some(value) = args.next().and_then(|value| value.parse().ok()) {
+                    limits.max_header_bytes = value;
+                }
+            }
+            "--max-body-bytes" => {
+                if let Some(value) = args.next().and_then(|value| value.parse().ok()) {
+                    limits.max_body_bytes = value;
+                }
+            }
+            _ => {}
         }
     }
 
+    let tls_acceptor = match (tls_cert, tls_key) {
+        (Some(cert_path), Some(key_path)) => Some(build_tls_acceptor(&cert_path, &key_path)?),
+        _ => None,
+    };
+
     loop {
-        let (mut socket, _) = listener.accept().await?;
+        let (socket, peer_addr) = listener.accept().await?;
 
         let directory = directory.clone();
-        tokio::spawn(async move {
-            write_response(&mut socket, directory).await;
-        });
+
+        match tls_acceptor.clone() {
+            Some(tls_acceptor) => {
+                tokio::spawn(async move {
+                    if let Ok(socket) = tls_acceptor.accept(socket).await {
+                        serve_connection(socket, peer_addr, directory, limits, log_ips).await;
+                    }
+                });
+            }
+            None => {
+                tokio::spawn(async move {
+                    serve_connection(socket, peer_addr, directory, limits, log_ips).await;
+                });
+            }
+        }
     }
 }
 
-async fn write(socket: &mut TcpStream, text: &str) {
-    let _ = socket.write(text.as_bytes()).await;
+/// Runs the keep-alive request loop for one already-accepted connection,
+/// plaintext or TLS alike, logging each request as it completes.
+async fn serve_connection<S: AsyncRead + AsyncWrite + Unpin>(
+    mut socket: S,
+    peer_addr: SocketAddr,
+    directory: Option<String>,
+    limits: Limits,
+    log_ips: bool,
+) {
+    let mut pending = Vec::new();
+    loop {
+        let mut handle = RequestHandle::new(
+            &mut socket,
+            peer_addr,
+            directory.clone(),
+            limits,
+            log_ips,
+            pending,
+        );
+        let state = handle.run().await;
+        pending = std::mem::take(&mut handle.pending);
+        match state {
+            ConnectionState::KeepAlive => {}
+            ConnectionState::Close => break,
+        }
+    }
+}
+
+/// Loads a PEM certificate chain and private key and builds the
+/// `rustls` server config used to wrap accepted sockets in TLS.
+fn build_tls_acceptor(cert_path: &str, key_path: &str) -> io::Result<TlsAcceptor> {
+    let mut cert_reader = io::BufReader::new(std::fs::File::open(cert_path)?);
+    let mut key_reader = io::BufReader::new(std::fs::File::open(key_path)?);
+
+    let cert_chain: Vec<CertificateDer<'static>> =
+        certs(&mut cert_reader).collect::<Result<_, _>>()?;
+    let key: PrivateKeyDer<'static> = private_key(&mut key_reader)?.ok_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidData, "no private key found in file")
+    })?;
+
+    let config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(cert_chain, key)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// Whether the connection a response was just written on should stay open
+/// for another request or be torn down.
+enum ConnectionState {
+    KeepAlive,
+    Close,
+}
+
+/// HTTP/1.1 connections default to keep-alive; the client opts out by
+/// sending `Connection: close`.
+fn wants_close(headers: &[Header]) -> bool {
+    headers.iter().any(|header| {
+        header.name.eq_ignore_ascii_case("Connection") && header.value.eq_ignore_ascii_case("close")
+    })
+}
+
+/// The static routes (`/`, `/user-agent`, `/echo/...`) only ever serve
+/// `GET`; `HEAD` gets the same response with the body withheld.
+fn is_get_or_head(method: &str) -> bool {
+    method == "GET" || method == "HEAD"
+}
+
+/// Parses the `Accept-Encoding` header (a comma-separated list of tokens,
+/// each optionally followed by a `;q=` weight) into the set of encoding
+/// names the client is willing to accept. A token with `q=0` means the
+/// client explicitly refuses that encoding, so it's dropped rather than
+/// treated as merely low-priority.
+fn parse_accept_encoding(headers: &[Header]) -> HashSet<String> {
+    headers
+        .iter()
+        .find(|header| header.name.eq_ignore_ascii_case("Accept-Encoding"))
+        .map(|header| {
+            header
+                .value
+                .split(',')
+                .filter_map(|token| {
+                    let mut parts = token.split(';');
+                    let name = parts.next().unwrap_or("").trim().to_lowercase();
+                    if name.is_empty() {
+                        return None;
+                    }
+
+                    let refused = parts.any(|param| {
+                        param
+                            .trim()
+                            .strip_prefix("q=")
+                            .and_then(|q| q.parse::<f32>().ok())
+                            .is_some_and(|q| q == 0.0)
+                    });
+
+                    if refused {
+                        None
+                    } else {
+                        Some(name)
+                    }
+                })
+                .collect()
+        })
+        .unwrap_or_default()
 }
 
-async fn write_response(socket: &mut TcpStream, directory: Option<String>) {
-    let request = match read_request(socket).await {
-        Ok(request) => request,
-        Err(_) => {
-            return;
+/// Given a response body and the client's accepted encodings, returns the
+/// bytes that should actually be sent along with any extra headers (e.g.
+/// `Content-Encoding`) that need to accompany them. Only `gzip` is
+/// supported today; unrecognized encodings fall through to the plain body.
+fn encode_body(
+    body: Vec<u8>,
+    accept_encoding: &HashSet<String>,
+) -> (Vec<u8>, Vec<(&'static str, String)>) {
+    if accept_encoding.contains("gzip") {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder
+            .write_all(&body)
+            .expect("writing to an in-memory gzip encoder cannot fail");
+        let compressed = encoder
+            .finish()
+            .expect("finishing an in-memory gzip encoder cannot fail");
+
+        (compressed, vec![("Content-Encoding", "gzip".to_string())])
+    } else {
+        (body, vec![])
+    }
+}
+
+fn extra_headers_to_string(extra_headers: &[(&'static str, String)]) -> String {
+    extra_headers
+        .iter()
+        .map(|(name, value)| format!("{}: {}\r\n", name, value))
+        .collect()
+}
+
+/// Owns one request's worth of work on an already-accepted connection:
+/// the socket, the configured directory and size limits, and an
+/// accumulating access-log line (peer address, method, path, status,
+/// bytes sent, elapsed time) that gets emitted once `run` finishes.
+/// `pending` carries any bytes already buffered past the end of this
+/// request (e.g. a pipelined next request arriving in the same read)
+/// so the next `RequestHandle` on this connection can pick up where
+/// this one left off instead of dropping them.
+struct RequestHandle<'a, S> {
+    socket: &'a mut S,
+    peer_addr: SocketAddr,
+    directory: Option<String>,
+    limits: Limits,
+    log_ips: bool,
+    pending: Vec<u8>,
+    method: String,
+    path: String,
+    status: u16,
+    bytes_sent: usize,
+    error: Option<RequestError>,
+    started_at: Instant,
+}
+
+impl<'a, S: AsyncRead + AsyncWrite + Unpin> RequestHandle<'a, S> {
+    fn new(
+        socket: &'a mut S,
+        peer_addr: SocketAddr,
+        directory: Option<String>,
+        limits: Limits,
+        log_ips: bool,
+        pending: Vec<u8>,
+    ) -> Self {
+        RequestHandle {
+            socket,
+            peer_addr,
+            directory,
+            limits,
+            log_ips,
+            pending,
+            method: String::new(),
+            path: String::new(),
+            status: 0,
+            bytes_sent: 0,
+            error: None,
+            started_at: Instant::now(),
         }
-    };
+    }
+
+    async fn run(&mut self) -> ConnectionState {
+        let state = self.dispatch().await;
+        self.log_outcome();
+        state
+    }
 
-    match request.path.as_str() {
-        "/" => {
-            write(socket, "HTTP/1.1 200 OK\r\n\r\n").await;
+    async fn write(&mut self, text: &str) {
+        self.write_bytes(text.as_bytes()).await;
+    }
+
+    async fn write_bytes(&mut self, bytes: &[u8]) {
+        if self.socket.write_all(bytes).await.is_ok() {
+            self.bytes_sent += bytes.len();
         }
-        "/user-agent" => {
-            let mut response =
-                "HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nContent-Length: 0\r\n\r\n"
-                    .to_string();
-
-            for header in request.headers {
-                if header.name == "User-Agent" {
-                    response = format!(
-                        "HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nContent-Length: {}\r\n\r\n{}",
-                        header.value.len(),
-                        header.value,
-                    );
-                    break;
-                }
+    }
+
+    async fn respond_method_not_allowed(&mut self, allowed: &[&str], connection_header: &str) {
+        self.status = 405;
+        self.write(&format!(
+            "HTTP/1.1 405 Method Not Allowed\r\nAllow: {}\r\n{}Content-Length: 0\r\n\r\n",
+            allowed.join(", "),
+            connection_header
+        ))
+        .await;
+    }
+
+    async fn dispatch(&mut self) -> ConnectionState {
+        let request = match self.read_request().await {
+            Ok(request) => request,
+            Err(RequestError::HeaderTooLarge) => {
+                self.status = 431;
+                self.error = Some(RequestError::HeaderTooLarge);
+                self.write("HTTP/1.1 431 Request Header Fields Too Large\r\n\r\n")
+                    .await;
+                return ConnectionState::Close;
+            }
+            Err(RequestError::BodyTooLarge) => {
+                self.status = 413;
+                self.error = Some(RequestError::BodyTooLarge);
+                self.write("HTTP/1.1 413 Payload Too Large\r\n\r\n").await;
+                return ConnectionState::Close;
+            }
+            Err(RequestError::Malformed) => {
+                self.status = 400;
+                self.error = Some(RequestError::Malformed);
+                self.write("HTTP/1.1 400 Bad Request\r\n\r\n").await;
+                return ConnectionState::Close;
             }
+            Err(e) => {
+                self.error = Some(e);
+                return ConnectionState::Close;
+            }
+        };
 
-            write(socket, &response).await;
-        }
-        s if s.starts_with("/echo/") => {
-            let mut split = s.splitn(2, "/echo/");
-
-            let message = match split.nth(1) {
-                Some(message) => message,
-                None => {
-                    write(socket, "HTTP/1.1 404 NOT FOUND\r\n\r\n").await;
-                    return;
+        self.method = request.method.clone();
+        self.path = request.path.clone();
+
+        let accept_encoding = parse_accept_encoding(&request.headers);
+        let close = wants_close(&request.headers);
+        let connection_header = if close { "Connection: close\r\n" } else { "" };
+
+        match request.path.as_str() {
+            "/" => {
+                if !is_get_or_head(&request.method) {
+                    self.respond_method_not_allowed(&["GET", "HEAD"], "Connection: close\r\n")
+                        .await;
+                    return ConnectionState::Close;
                 }
-            };
 
-            write(
-                socket,
-                format!(
-                    "HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nContent-Length: {}\r\n\r\n{}",
-                    message.len(),
-                    message,
-                )
-                .as_str(),
-            )
-            .await;
-        }
-        s if s.starts_with("/files/") => {
-            let mut split = s.splitn(2, "/files/");
-
-            let directory = match directory {
-                Some(directory) => directory,
-                None => {
-                    write(socket, "HTTP/1.1 404 NOT FOUND\r\n\r\n").await;
-                    return;
+                self.status = 200;
+                self.write(&format!(
+                    "HTTP/1.1 200 OK\r\n{}Content-Length: 0\r\n\r\n",
+                    connection_header
+                ))
+                .await;
+            }
+            "/user-agent" => {
+                if !is_get_or_head(&request.method) {
+                    self.respond_method_not_allowed(&["GET", "HEAD"], "Connection: close\r\n")
+                        .await;
+                    return ConnectionState::Close;
                 }
-            };
 
-            let file = match split.nth(1) {
-                Some(file) => file,
-                None => {
-                    write(socket, "HTTP/1.1 404 NOT FOUND\r\n\r\n").await;
-                    return;
+                let mut response = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\n{}Content-Length: 0\r\n\r\n",
+                    connection_header
+                );
+                let mut body = "";
+
+                for header in &request.headers {
+                    if header.name == "User-Agent" {
+                        response = format!(
+                            "HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\n{}Content-Length: {}\r\n\r\n",
+                            connection_header,
+                            header.value.len(),
+                        );
+                        body = header.value.as_str();
+                        break;
+                    }
                 }
-            };
 
-            let path = format!("{}/{}", directory, file);
+                self.status = 200;
+                self.write(&response).await;
+                if request.method != "HEAD" {
+                    self.write(body).await;
+                }
+            }
+            s if s.starts_with("/echo/") => {
+                if !is_get_or_head(&request.method) {
+                    self.respond_method_not_allowed(&["GET", "HEAD"], "Connection: close\r\n")
+                        .await;
+                    return ConnectionState::Close;
+                }
 
-            handle_files(socket, request, path.as_str()).await;
-        }
-        _ => {
-            write(socket, "HTTP/1.1 404 NOT FOUND\r\n\r\n").await;
-        }
-    };
-}
+                let mut split = s.splitn(2, "/echo/");
 
-async fn handle_files(socket: &mut TcpStream, request: Request, file_path: &str) {
-    match request.method.as_str() {
-        "GET" => {
-            let content_length = match tokio::fs::metadata(file_path).await {
-                Ok(metadata) => metadata.len(),
-                Err(_) => {
-                    write(socket, "HTTP/1.1 404 NOT FOUND\r\n\r\n").await;
-                    return;
+                let message = match split.nth(1) {
+                    Some(message) => message,
+                    None => {
+                        self.status = 404;
+                        self.write("HTTP/1.1 404 NOT FOUND\r\nConnection: close\r\nContent-Length: 0\r\n\r\n")
+                            .await;
+                        return ConnectionState::Close;
+                    }
+                };
+
+                let (body, extra_headers) =
+                    encode_body(message.as_bytes().to_vec(), &accept_encoding);
+
+                self.status = 200;
+                self.write(&format!(
+                    "HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\n{}{}Content-Length: {}\r\n\r\n",
+                    connection_header,
+                    extra_headers_to_string(&extra_headers),
+                    body.len(),
+                ))
+                .await;
+                if request.method != "HEAD" {
+                    self.write_bytes(&body).await;
                 }
-            };
+            }
+            s if s.starts_with("/files/") => {
+                let mut split = s.splitn(2, "/files/");
+
+                let directory = match self.directory.clone() {
+                    Some(directory) => directory,
+                    None => {
+                        self.status = 404;
+                        self.write("HTTP/1.1 404 NOT FOUND\r\nConnection: close\r\nContent-Length: 0\r\n\r\n")
+                            .await;
+                        return ConnectionState::Close;
+                    }
+                };
+
+                let file = match split.nth(1) {
+                    Some(file) => file,
+                    None => {
+                        self.status = 404;
+                        self.write("HTTP/1.1 404 NOT FOUND\r\nConnection: close\r\nContent-Length: 0\r\n\r\n")
+                            .await;
+                        return ConnectionState::Close;
+                    }
+                };
 
-            let mut file = match tokio::fs::File::open(file_path).await {
-                Ok(file) => file,
-                Err(_) => {
-                    write(socket, "HTTP/1.1 404 NOT FOUND\r\n\r\n").await;
-                    return;
-                }
-            };
+                let path = format!("{}/{}", directory, file);
 
-            write(socket, format!("HTTP/1.1 200 OK\r\nContent-Type: application/octet-stream\r\nContent-Length: {}\r\n\r\n", content_length).as_str()).await;
+                self.handle_files(request, path.as_str(), &accept_encoding, connection_header)
+                    .await;
+            }
+            _ => {
+                self.status = 404;
+                self.write("HTTP/1.1 404 NOT FOUND\r\nContent-Length: 0\r\n\r\n")
+                    .await;
+            }
+        };
 
-            let mut buffer = [0; 1024];
-            loop {
-                match file.read(&mut buffer).await {
-                    Ok(0) => break,
-                    Ok(n) => {
-                        let _ = socket.write(&buffer[0..n]).await;
-                    }
+        if close {
+            ConnectionState::Close
+        } else {
+            ConnectionState::KeepAlive
+        }
+    }
+
+    async fn handle_files(
+        &mut self,
+        request: Request,
+        file_path: &str,
+        accept_encoding: &HashSet<String>,
+        connection_header: &str,
+    ) {
+        match request.method.as_str() {
+            "GET" | "HEAD" => {
+                let contents = match tokio::fs::read(file_path).await {
+                    Ok(contents) => contents,
                     Err(_) => {
-                        write(socket, "HTTP/1.1 404 NOT FOUND\r\n\r\n").await;
+                        self.status = 404;
+                        self.write("HTTP/1.1 404 NOT FOUND\r\nContent-Length: 0\r\n\r\n")
+                            .await;
                         return;
                     }
+                };
+
+                let (body, extra_headers) = encode_body(contents, accept_encoding);
+
+                self.status = 200;
+                self.write(&format!(
+                    "HTTP/1.1 200 OK\r\nContent-Type: application/octet-stream\r\n{}{}Content-Length: {}\r\n\r\n",
+                    connection_header,
+                    extra_headers_to_string(&extra_headers),
+                    body.len(),
+                ))
+                .await;
+                if request.method != "HEAD" {
+                    self.write_bytes(&body).await;
                 }
             }
+            "POST" => {
+                let mut file = match tokio::fs::File::create(file_path).await {
+                    Ok(file) => file,
+                    Err(_) => {
+                        self.status = 404;
+                        self.write("HTTP/1.1 404 NOT FOUND\r\nContent-Length: 0\r\n\r\n")
+                            .await;
+                        return;
+                    }
+                };
+
+                let _ = file.write_all(&request.content).await;
+
+                self.status = 201;
+                self.write(&format!(
+                    "HTTP/1.1 201 CREATED\r\n{}Content-Length: 0\r\n\r\n",
+                    connection_header
+                ))
+                .await;
+            }
+            _ => {
+                self.respond_method_not_allowed(&["GET", "HEAD", "POST"], connection_header)
+                    .await;
+            }
+        }
+    }
+
+    /// Reads one full request off the socket: buffers bytes until the
+    /// `\r\n\r\n` header terminator has arrived, parses the request line
+    /// and headers out of that, then reads exactly as many further bytes
+    /// as `Content-Length` calls for, carrying over whatever body bytes
+    /// had already been buffered past the terminator (e.g. from a
+    /// pipelined next request arriving in the same read). Bails out
+    /// early, before allocating anything of the offending size, if the
+    /// headers or body exceed `self.limits`.
+    async fn read_request(&mut self) -> Result<Request, RequestError> {
+        let mut buffer = std::mem::take(&mut self.pending);
+
+        let header_end = loop {
+            if let Some(pos) = find_header_terminator(&buffer) {
+                break pos;
+            }
+            if buffer.len() >= self.limits.max_header_bytes {
+                return Err(RequestError::HeaderTooLarge);
+            }
+            let mut chunk = [0; 1024];
+            let n = self
+                .socket
+                .read(&mut chunk)
+                .await
+                .map_err(|_| RequestError::ConnectionClosed)?;
+            if n == 0 {
+                return Err(if buffer.is_empty() {
+                    RequestError::IdleClose
+                } else {
+                    RequestError::ConnectionClosed
+                });
+            }
+            buffer.extend_from_slice(&chunk[..n]);
+        };
+
+        if header_end + 4 > self.limits.max_header_bytes {
+            return Err(RequestError::HeaderTooLarge);
+        }
+
+        let mut request = match parse_request(&buffer) {
+            Ok((_, request)) => request,
+            Err(_) => return Err(RequestError::Malformed),
+        };
+
+        if has_chunked_encoding(&request.headers) {
+            let (content, leftover) = self.read_chunked_body(request.content.clone()).await?;
+            request.content = content;
+            self.pending = leftover;
+            return Ok(request);
         }
-        "POST" => {
-            let mut file = match tokio::fs::File::create(file_path).await {
-                Ok(file) => file,
-                Err(_) => {
-                    write(socket, "HTTP/1.1 404 NOT FOUND\r\n\r\n").await;
-                    return;
+
+        let content_length = content_length(&request.headers);
+        if content_length > self.limits.max_body_bytes {
+            return Err(RequestError::BodyTooLarge);
+        }
+
+        while request.content.len() < content_length {
+            let mut chunk = [0; 1024];
+            let n = self
+                .socket
+                .read(&mut chunk)
+                .await
+                .map_err(|_| RequestError::ConnectionClosed)?;
+            if n == 0 {
+                return Err(RequestError::ConnectionClosed);
+            }
+            request.content.extend_from_slice(&chunk[..n]);
+        }
+        if request.content.len() > content_length {
+            self.pending = request.content.split_off(content_length);
+        } else {
+            request.content.truncate(content_length);
+        }
+
+        Ok(request)
+    }
+
+    /// Decodes a `Transfer-Encoding: chunked` body, starting from whatever
+    /// bytes `read_request` already buffered past the headers. Each chunk
+    /// is a hex length, an optional `;`-delimited extension (ignored), a
+    /// CRLF, that many bytes of data, then a trailing CRLF; a zero-length
+    /// chunk ends the stream. Trailers after the final chunk aren't
+    /// supported, matching this server's existing header-only-at-the-top
+    /// parsing. The running total is checked against `max_body_bytes`
+    /// before each chunk is read, so an attacker can't force a large
+    /// allocation through many small chunk-size lies. Returns the decoded
+    /// body along with any bytes left over past the terminating chunk
+    /// (e.g. a pipelined next request), which the caller must hand to the
+    /// next `read_request` instead of discarding.
+    async fn read_chunked_body(
+        &mut self,
+        mut leftover: Vec<u8>,
+    ) -> Result<(Vec<u8>, Vec<u8>), RequestError> {
+        let mut body = Vec::new();
+
+        loop {
+            let line_end = loop {
+                if let Some(pos) = find_crlf(&leftover) {
+                    break pos;
                 }
+                self.fill(&mut leftover).await?;
             };
+            let size_line = &leftover[..line_end];
+            let size_line = size_line.split(|&b| b == b';').next().unwrap_or(size_line);
+            let size_str = std::str::from_utf8(size_line).map_err(|_| RequestError::Malformed)?;
+            let chunk_size =
+                usize::from_str_radix(size_str.trim(), 16).map_err(|_| RequestError::Malformed)?;
+            leftover.drain(..line_end + 2);
+
+            if chunk_size == 0 {
+                while leftover.len() < 2 {
+                    self.fill(&mut leftover).await?;
+                }
+                if &leftover[..2] != b"\r\n" {
+                    return Err(RequestError::Malformed);
+                }
+                leftover.drain(..2);
+                break;
+            }
 
-            let _ = file.write(&request.content).await;
+            if body.len() + chunk_size > self.limits.max_body_bytes {
+                return Err(RequestError::BodyTooLarge);
+            }
 
-            let mut buffer = [0; 1024];
-            loop {
-                match socket.try_read(&mut buffer) {
-                    Ok(0) => break,
-                    Ok(n) => {
-                        let _ = file.write(&buffer[0..n]).await;
-                    }
-                    Err(_) => (),
-                }
+            while leftover.len() < chunk_size + 2 {
+                self.fill(&mut leftover).await?;
             }
+            if &leftover[chunk_size..chunk_size + 2] != b"\r\n" {
+                return Err(RequestError::Malformed);
+            }
+            body.extend_from_slice(&leftover[..chunk_size]);
+            leftover.drain(..chunk_size + 2);
+        }
 
-            write(socket, "HTTP/1.1 201 CREATED\r\n\r\n").await;
+        Ok((body, leftover))
+    }
+
+    /// Reads more bytes from the socket into `buffer`, treating EOF and IO
+    /// errors alike as a connection that closed mid-request.
+    async fn fill(&mut self, buffer: &mut Vec<u8>) -> Result<(), RequestError> {
+        let mut chunk = [0; 1024];
+        let n = self
+            .socket
+            .read(&mut chunk)
+            .await
+            .map_err(|_| RequestError::ConnectionClosed)?;
+        if n == 0 {
+            return Err(RequestError::ConnectionClosed);
         }
-        _ => {
-            write(socket, "HTTP/1.1 404 NOT FOUND\r\n\r\n").await;
+        buffer.extend_from_slice(&chunk[..n]);
+        Ok(())
+    }
+
+    /// Emits the accumulated access-log line: successful requests (even
+    /// ones answered with a 4xx) at info level, and requests that never
+    /// made it through `read_request` at warn level, since those used
+    /// to be silently discarded. The exception is `IdleClose`: a
+    /// keep-alive client closing the socket between requests is routine
+    /// teardown, not a failure, so it's logged at info level too.
+    fn log_outcome(&self) {
+        let peer = if self.log_ips {
+            self.peer_addr.to_string()
+        } else {
+            "-".to_string()
+        };
+        let method = if self.method.is_empty() {
+            "-"
+        } else {
+            self.method.as_str()
+        };
+        let path = if self.path.is_empty() {
+            "-"
+        } else {
+            self.path.as_str()
+        };
+        let elapsed = self.started_at.elapsed();
+
+        match &self.error {
+            Some(RequestError::IdleClose) => info!("{peer} connection closed {elapsed:?}"),
+            Some(error) => warn!(
+                "{peer} {method} {path} {status} {bytes}B {elapsed:?} error={error}",
+                status = self.status,
+                bytes = self.bytes_sent,
+            ),
+            None => info!(
+                "{peer} {method} {path} {status} {bytes}B {elapsed:?}",
+                status = self.status,
+                bytes = self.bytes_sent,
+            ),
         }
     }
 }
 
-async fn read_request(stream: &mut TcpStream) -> Result<Request, Box<dyn Error>> {
-    let mut buffer = [0; 1024];
-    let _ = stream.read(&mut buffer).await;
+/// Why `RequestHandle::read_request` gave up. `HeaderTooLarge`,
+/// `BodyTooLarge` and `Malformed` are surfaced to the client as
+/// `431`/`413`/`400`; `ConnectionClosed` and `IdleClose` just close the
+/// connection since there's nobody left to write a response to.
+/// `IdleClose` is the routine case of a keep-alive client closing the
+/// socket between requests rather than mid-request, and is logged
+/// accordingly (see `log_outcome`).
+#[derive(Debug)]
+enum RequestError {
+    ConnectionClosed,
+    IdleClose,
+    HeaderTooLarge,
+    BodyTooLarge,
+    Malformed,
+}
 
-    let request = match parse_request(&buffer) {
-        Ok((_, request)) => request,
-        Err(e) => {
-            return Err(Box::new(e.to_owned()));
+impl std::fmt::Display for RequestError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RequestError::ConnectionClosed => {
+                write!(f, "connection closed before request was complete")
+            }
+            RequestError::IdleClose => write!(f, "connection closed between requests"),
+            RequestError::HeaderTooLarge => write!(f, "request header fields too large"),
+            RequestError::BodyTooLarge => write!(f, "request body too large"),
+            RequestError::Malformed => write!(f, "malformed request"),
         }
-    };
+    }
+}
+
+impl Error for RequestError {}
+
+fn find_header_terminator(buffer: &[u8]) -> Option<usize> {
+    buffer.windows(4).position(|window| window == b"\r\n\r\n")
+}
+
+fn find_crlf(buffer: &[u8]) -> Option<usize> {
+    buffer.windows(2).position(|window| window == b"\r\n")
+}
+
+fn content_length(headers: &[Header]) -> usize {
+    headers
+        .iter()
+        .find(|header| header.name.eq_ignore_ascii_case("Content-Length"))
+        .and_then(|header| header.value.trim().parse().ok())
+        .unwrap_or(0)
+}
 
-    Ok(request)
+fn has_chunked_encoding(headers: &[Header]) -> bool {
+    headers
+        .iter()
+        .find(|header| header.name.eq_ignore_ascii_case("Transfer-Encoding"))
+        .is_some_and(|header| {
+            header
+                .value
+                .split(',')
+                .any(|token| token.trim().eq_ignore_ascii_case("chunked"))
+        })
 }
 
 fn parse_request(input: &[u8]) -> IResult<&[u8], Request> {
@@ -238,7 +819,14 @@ fn space(input: &[u8]) -> IResult<&[u8], &[u8]> {
 }
 
 fn method(input: &[u8]) -> IResult<&[u8], &[u8]> {
-    alt((tag("GET"), tag("POST")))(input)
+    alt((
+        tag("HEAD"),
+        tag("GET"),
+        tag("POST"),
+        tag("PUT"),
+        tag("DELETE"),
+        tag("OPTIONS"),
+    ))(input)
 }
 
 fn path(input: &[u8]) -> IResult<&[u8], &[u8]> {
@@ -258,7 +846,7 @@ fn headers(input: &[u8]) -> IResult<&[u8], Vec<Header>> {
 }
 
 fn header(input: &[u8]) -> IResult<&[u8], Header> {
-    let (input, name) = take_while(|c| c != b':')(input)?;
+    let (input, name) = take_while(|c| c != b':' && c != b'\r')(input)?;
     let (input, _) = tag(": ")(input)?;
     let (input, value) = take_while(|c| c != b'\r')(input)?;
     let (input, _) = crlf(input)?;